@@ -0,0 +1,58 @@
+use super::Node;
+use std::collections::HashMap;
+
+/// A small bounded cache of decoded tree `Node`s, evicted least-recently-used
+/// first. Repeated `get_node`/`data_offset` lookups for hot ancestors then
+/// hit memory instead of re-reading and re-decoding the `tree` store.
+/// A `capacity` of `0` disables the cache entirely.
+pub struct NodeCache {
+  capacity: usize,
+  entries: HashMap<usize, Node>,
+  order: Vec<usize>,
+}
+
+impl NodeCache {
+  /// Create a cache that holds at most `capacity` nodes.
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: HashMap::new(),
+      order: Vec::new(),
+    }
+  }
+
+  /// Look up `index`, marking it as most-recently-used on a hit.
+  pub fn get(&mut self, index: usize) -> Option<Node> {
+    let node = self.entries.get(&index).cloned();
+    if node.is_some() {
+      self.touch(index);
+    }
+    node
+  }
+
+  /// Insert or update `index`, evicting the least-recently-used entry if
+  /// the cache is over capacity.
+  pub fn put(&mut self, index: usize, node: Node) {
+    if self.capacity == 0 {
+      return;
+    }
+
+    if self.entries.insert(index, node).is_some() {
+      self.touch(index);
+      return;
+    }
+
+    self.order.push(index);
+    if self.order.len() > self.capacity {
+      let oldest = self.order.remove(0);
+      self.entries.remove(&oldest);
+    }
+  }
+
+  fn touch(&mut self, index: usize) {
+    if let Some(pos) = self.order.iter().position(|&i| i == index) {
+      let index = self.order.remove(pos);
+      self.order.push(index);
+    }
+  }
+}
@@ -1,5 +1,6 @@
 //! Save data to a desired storage backend.
 
+extern crate blake2_rfc;
 extern crate failure;
 extern crate flat_tree as flat;
 extern crate random_access_disk as rad;
@@ -7,6 +8,7 @@ extern crate random_access_memory as ram;
 extern crate random_access_storage as ras;
 extern crate sleep_parser;
 
+mod cache;
 mod data;
 mod node;
 mod signature;
@@ -15,6 +17,9 @@ pub use self::data::Data;
 pub use self::node::Node;
 pub use self::signature::Signature;
 
+use self::cache::NodeCache;
+
+use self::blake2_rfc::blake2b::Blake2b;
 use self::failure::Error;
 use self::ras::SyncMethods;
 use self::sleep_parser::*;
@@ -49,7 +54,7 @@ where
   data: ras::Sync<T>,
   bitfield: ras::Sync<T>,
   signatures: ras::Sync<T>,
-  // cache_size
+  cache: NodeCache,
 }
 
 impl<T> Storage<T>
@@ -71,6 +76,7 @@ where
       data: create(Store::Data),
       bitfield: create(Store::Bitfield),
       signatures: create(Store::Signatures),
+      cache: NodeCache::new(0),
     };
 
     let header = create_bitfield();
@@ -85,6 +91,72 @@ where
     Ok(instance)
   }
 
+  /// Create an instance from storage that may already hold a SLEEP archive.
+  /// Stores that are empty get a fresh header written, same as
+  /// `with_storage`; stores that already have bytes get their header
+  /// validated instead of overwritten, so reopening a real feed doesn't
+  /// clobber it.
+  pub fn open<Cb>(key_pair: KeyPair, create: Cb) -> Result<Self, Error>
+  where
+    Cb: Fn(Store) -> ras::Sync<T>,
+  {
+    let mut instance = Self {
+      public_key: key_pair.public_key,
+      secret_key: key_pair.secret_key,
+      tree: create(Store::Tree),
+      data: create(Store::Data),
+      bitfield: create(Store::Bitfield),
+      signatures: create(Store::Signatures),
+      cache: NodeCache::new(0),
+    };
+
+    instance.init_or_validate_header("bitfield", Store::Bitfield, &create_bitfield().to_vec())?;
+    instance.init_or_validate_header("signatures", Store::Signatures, &create_signatures().to_vec())?;
+    instance.init_or_validate_header("tree", Store::Tree, &create_tree().to_vec())?;
+
+    Ok(instance)
+  }
+
+  /// Same as `open`, but decoded tree nodes are kept in a bounded LRU cache
+  /// of `cache_size` entries, so hot ancestors don't round-trip through the
+  /// `tree` store on every lookup. A `cache_size` of `0` disables the cache.
+  pub fn with_cache<Cb>(
+    key_pair: KeyPair,
+    create: Cb,
+    cache_size: usize,
+  ) -> Result<Self, Error>
+  where
+    Cb: Fn(Store) -> ras::Sync<T>,
+  {
+    let mut instance = Self::open(key_pair, create)?;
+    instance.cache = NodeCache::new(cache_size);
+    Ok(instance)
+  }
+
+  /// Write `expected` as the header of `store` if it's empty, otherwise read
+  /// back the existing header and check it against `expected` field by
+  /// field.
+  fn init_or_validate_header(
+    &mut self,
+    name: &str,
+    store: Store,
+    expected: &[u8],
+  ) -> Result<(), Error> {
+    let handle = match store {
+      Store::Tree => &mut self.tree,
+      Store::Signatures => &mut self.signatures,
+      Store::Bitfield => &mut self.bitfield,
+      Store::Data => unreachable!("the data store has no SLEEP header"),
+    };
+
+    if handle.len()? == 0 {
+      return handle.write(0, expected);
+    }
+
+    let existing = handle.read(0, HEADER_OFFSET)?;
+    validate_header(name, &existing, expected)
+  }
+
   /// Write `Data` to `self.Data`.
   /// TODO: Ensure the signature size is correct.
   /// NOTE: Should we create a `Signature` entry type?
@@ -92,7 +164,7 @@ where
     &mut self,
     index: usize,
     data: &[u8],
-    nodes: &[u8],
+    nodes: &[Node],
   ) -> Result<(), Error> {
     if data.is_empty() {
       return Ok(());
@@ -103,9 +175,10 @@ where
     self.data.write(offset, data)
   }
 
-  /// TODO(yw) docs
-  pub fn get_data(&mut self) {
-    unimplemented!();
+  /// Read block `index` back from the `data` store.
+  pub fn get_data(&mut self, index: usize) -> Result<Vec<u8>, Error> {
+    let (offset, size) = self.data_offset(index, &[])?;
+    self.data.read(offset, size)
   }
 
   /// TODO(yw) docs
@@ -131,37 +204,54 @@ where
       .write(HEADER_OFFSET + 64 * index, signature)
   }
 
-  /// TODO(yw) docs
-  /// Get the offset for the data, return `(offset, size)`.
+  /// Get the byte offset and size of block `index` inside the `data` store,
+  /// return `(offset, size)`.
+  ///
+  /// NOTE: the leaf lookup for `index` itself treats *any* `get_node`
+  /// failure as "block not written yet" and reports size `0`. The `ras`
+  /// backends don't currently distinguish a short read past the end of the
+  /// store from a genuine I/O error, so a real disk failure on this path is
+  /// indistinguishable from a sparse/not-yet-replicated block.
   pub fn data_offset(
     &mut self,
     index: usize,
-    cached_nodes: &[u8],
+    cached_nodes: &[Node],
   ) -> Result<(usize, usize), Error> {
     let mut roots = Vec::new(); // FIXME: reuse alloc
     flat::full_roots(2 * index, &mut roots);
-    let mut offset = 0;
-    let mut pending = roots.len();
-    let blk = 2 * index;
 
-    if pending == 0 {
-      pending = 1;
-      // onnode(null, null)
-      return Ok((0, 0)); // TODO: fixme
+    let mut offset = 0;
+    for root in roots {
+      let node = match find_node(cached_nodes, root) {
+        Some(node) => node,
+        None => self.get_node(root)?,
+      };
+      offset += node.size();
     }
 
-    // for root in roots {
-    //   match find_node(cached_nodes, root) {
-    //     Some(node) => onnode,
-    //   }
-    // }
-    unimplemented!();
+    let blk = 2 * index;
+    let size = match find_node(cached_nodes, blk) {
+      Some(node) => node.size(),
+      None => match self.get_node(blk) {
+        Ok(node) => node.size(),
+        Err(_) => 0,
+      },
+    };
+
+    Ok((offset, size))
   }
 
-  /// Get a `Node` from the `tree` storage.
+  /// Get a `Node` from the `tree` storage, serving it from the node cache
+  /// when possible.
   pub fn get_node(&mut self, index: usize) -> Result<Node, Error> {
+    if let Some(node) = self.cache.get(index) {
+      return Ok(node);
+    }
+
     let buf = self.tree.read(HEADER_OFFSET + 40 * index, 40)?;
-    Node::from_vec(index, &buf)
+    let node = Node::from_vec(index, &buf)?;
+    self.cache.put(index, node.clone());
+    Ok(node)
   }
 
   /// TODO(yw) docs
@@ -174,9 +264,9 @@ where
     node: &mut Node,
   ) -> Result<(), Error> {
     let buf = node.to_vec()?;
-    self
-      .tree
-      .write(HEADER_OFFSET + 40 * index, &buf)
+    self.tree.write(HEADER_OFFSET + 40 * index, &buf)?;
+    self.cache.put(index, node.clone());
+    Ok(())
   }
 
   /// Write data to the internal bitfield module.
@@ -192,10 +282,245 @@ where
       .write(HEADER_OFFSET + offset, data)
   }
 
+  /// Append a new data block, writing its bytes, the new tree nodes it
+  /// completes, and a fresh signature over the updated root set, then
+  /// marking it present in the bitfield. Returns the new block's index.
+  pub fn append(&mut self, data: &[u8]) -> Result<usize, Error> {
+    let index = self.block_count()?;
+    let leaf_index = 2 * index;
+
+    let mut node = Node::new(leaf_index, hash_leaf(data), data.len());
+    self.put_node(leaf_index, &mut node)?;
+
+    let mut current = node;
+    loop {
+      let sibling_index = flat::sibling(current.index());
+      if sibling_index >= current.index() {
+        break; // left child: no right sibling yet, so it stays a root.
+      }
+
+      let sibling = match self.get_node(sibling_index) {
+        Ok(sibling) => sibling,
+        Err(_) => break, // sibling hasn't been written yet, so it stays a root.
+      };
+
+      let parent_index = flat::parent(current.index());
+      let size = sibling.size() + current.size();
+      let hash = hash_parent(size, sibling.hash(), current.hash());
+      let mut parent = Node::new(parent_index, hash, size);
+      self.put_node(parent_index, &mut parent)?;
+      current = parent;
+    }
+
+    let (offset, size) = self.data_offset(index, &[])?;
+    ensure!(size == data.len(), "Unexpected size for block {}", index);
+    self.data.write(offset, data)?;
+
+    let mut roots = Vec::new();
+    flat::full_roots(2 * (index + 1), &mut roots);
+    let mut message = Vec::new();
+    for &root in &roots {
+      message.extend_from_slice(self.get_node(root)?.hash());
+    }
+    let signature = self.secret_key.sign(&message);
+    self.put_signature(index, &signature.to_bytes())?;
+
+    let byte = index / 8;
+    let mut bits = self
+      .bitfield
+      .read(HEADER_OFFSET + byte, 1)
+      .unwrap_or_else(|_| vec![0]);
+    bits[0] |= bit_mask(index);
+    self.put_bitfield(byte, &bits)?;
+
+    Ok(index)
+  }
+
+  /// Is block `index` present in the `data` store?
+  pub fn has(&mut self, index: usize) -> Result<bool, Error> {
+    let byte = self.bitfield_byte(index / 8)?;
+    Ok(byte & bit_mask(index) != 0)
+  }
+
+  /// Are all blocks in `[start, end)` present?
+  pub fn has_range(&mut self, start: usize, end: usize) -> Result<bool, Error> {
+    if start >= end {
+      return Ok(true);
+    }
+
+    let start_byte = start / 8;
+    let end_byte = (end - 1) / 8;
+
+    for byte_index in start_byte..=end_byte {
+      let byte = self.bitfield_byte(byte_index)?;
+      if byte == 0xff {
+        continue;
+      }
+
+      let lo = if byte_index == start_byte { start % 8 } else { 0 };
+      let hi = if byte_index == end_byte {
+        ((end - 1) % 8) + 1
+      } else {
+        8
+      };
+
+      for bit in lo..hi {
+        if byte & (0x80 >> bit) == 0 {
+          return Ok(false);
+        }
+      }
+    }
+
+    Ok(true)
+  }
+
+  /// Read one byte of the bitfield, treating a byte that hasn't been
+  /// written yet (e.g. a sparsely-replicated feed that doesn't hold this
+  /// part of the bitfield locally) as all-absent rather than an I/O error.
+  ///
+  /// NOTE: this maps *every* read error to "absent", not just a short read
+  /// past the end of the store, because the `ras` backends don't currently
+  /// expose a way to tell the two apart. A real I/O failure (disk error,
+  /// permissions) on this path is reported as "block absent" rather than
+  /// surfaced to the caller — don't rely on `has`/`has_range`/`has_all` as a
+  /// proxy for "storage is healthy".
+  fn bitfield_byte(&mut self, byte_index: usize) -> Result<u8, Error> {
+    match self.bitfield.read(HEADER_OFFSET + byte_index, 1) {
+      Ok(buf) => Ok(buf[0]),
+      Err(_) => Ok(0),
+    }
+  }
+
+  /// Are all blocks known to the `tree` store present in `data`?
+  pub fn has_all(&mut self) -> Result<bool, Error> {
+    let blocks = self.block_count()?;
+    self.has_range(0, blocks)
+  }
+
   /// TODO(yw) docs
   pub fn open_key(&mut self) {
     unimplemented!();
   }
+
+  /// The feed's discovery key: the keyed BLAKE2b hash of the constant
+  /// string `"hypercore"`, using the public key as the BLAKE2b key. Peers
+  /// use this — not the public key itself — as the swarm topic when
+  /// announcing or looking up a feed, so it must be derivable without the
+  /// secret key.
+  pub fn discovery_key(&self) -> [u8; 32] {
+    let mut hasher = Blake2b::with_key(32, self.public_key.as_bytes());
+    hasher.update(b"hypercore");
+
+    let mut discovery_key = [0; 32];
+    discovery_key.copy_from_slice(hasher.finalize().as_bytes());
+    discovery_key
+  }
+
+  /// Validate every stored data block against the Merkle tree, and the
+  /// current root hashes against the latest signature. Returns an error
+  /// describing the first mismatch found.
+  pub fn verify(&mut self) -> Result<(), Error> {
+    let blocks = self.block_count()?;
+    if blocks == 0 {
+      return Ok(());
+    }
+
+    let mut roots = Vec::new();
+    flat::full_roots(2 * blocks, &mut roots);
+
+    let mut message = Vec::new();
+    for &root in &roots {
+      message.extend_from_slice(&self.verify_node(root)?);
+    }
+
+    // `append()` writes one signature per block, each over the
+    // concatenation of the *current* root hashes, so only the latest
+    // signature describes the feed's present root set.
+    let index = blocks - 1;
+    let buf = self.signatures.read(HEADER_OFFSET + 64 * index, 64)?;
+    let signature = Signature::from_vec(index, &buf)?;
+    signature.verify(&self.public_key, &message)
+  }
+
+  /// Recompute the hash of `index`, recursing into its children, and check
+  /// it against the `hash` field of the stored `Node`. Returns the computed
+  /// hash so a parent call can fold it into its own hash.
+  fn verify_node(&mut self, index: usize) -> Result<Vec<u8>, Error> {
+    let node = self.get_node(index)?;
+
+    let computed = match flat::children(index) {
+      Some((left, right)) => {
+        let left_hash = self.verify_node(left)?;
+        let right_hash = self.verify_node(right)?;
+        hash_parent(node.size(), &left_hash, &right_hash)
+      }
+      None => {
+        let data = self.get_data(index / 2)?;
+        hash_leaf(&data)
+      }
+    };
+
+    ensure!(
+      computed == node.hash(),
+      "Hash mismatch for tree node {}",
+      index
+    );
+
+    Ok(computed)
+  }
+
+  /// Cheap structural sanity check: do the `tree`, `data` and `signatures`
+  /// stores have mutually consistent lengths? The `bitfield` store is only
+  /// checked to hold its header; its length is not compared with the block
+  /// count. Unlike `verify`, this never reads block data or checks hashes.
+  pub fn check(&mut self) -> Result<(), Error> {
+    let tree_len = self.tree.len()? as usize;
+    let sig_len = self.signatures.len()? as usize;
+    let bitfield_len = self.bitfield.len()? as usize;
+
+    ensure!(tree_len >= HEADER_OFFSET, "tree store is missing its header");
+    ensure!(
+      sig_len >= HEADER_OFFSET,
+      "signatures store is missing its header"
+    );
+    ensure!(
+      bitfield_len >= HEADER_OFFSET,
+      "bitfield store is missing its header"
+    );
+
+    // `append()` writes exactly one signature per block, so signature
+    // count tracks the number of appended blocks, not the current number
+    // of tree roots (which shrinks every time an append completes a pair).
+    let blocks = self.block_count()?;
+    let signature_count = (sig_len - HEADER_OFFSET) / 64;
+    ensure!(
+      signature_count == blocks,
+      "signature count ({}) does not match block count ({})",
+      signature_count,
+      blocks
+    );
+
+    let (expected_data_len, _) = self.data_offset(blocks, &[])?;
+    let data_len = self.data.len()? as usize;
+    ensure!(
+      data_len == expected_data_len,
+      "data store length ({}) does not match the length implied by the tree ({})",
+      data_len,
+      expected_data_len
+    );
+
+    Ok(())
+  }
+
+  /// Number of data blocks currently recorded in the `tree` store.
+  fn block_count(&mut self) -> Result<usize, Error> {
+    let tree_len = self.tree.len()? as usize;
+    if tree_len <= HEADER_OFFSET {
+      return Ok(0);
+    }
+    let nodes = (tree_len - HEADER_OFFSET) / 40;
+    Ok((nodes + 1) / 2)
+  }
 }
 
 impl Storage<self::rad::SyncMethods> {
@@ -204,7 +529,7 @@ impl Storage<self::rad::SyncMethods> {
   // NOTE: Should we `mkdirp` here?
   // NOTE: Should we call these `data.bitfield` / `data.tree`?
   pub fn new(key_pair: KeyPair, dir: PathBuf) -> Result<Self, Error> {
-    Self::with_storage(key_pair, |storage: Store| {
+    Self::open(key_pair, |storage: Store| {
       let name = match storage {
         Store::Tree => "tree",
         Store::Data => "data",
@@ -224,13 +549,200 @@ impl Default for Storage<self::ram::SyncMethods> {
   }
 }
 
-/// Get a node from a vector of nodes.
-// TODO: define type of node
-fn find_node(nodes: Vec<Node>, index: usize) -> Option<Node> {
-  for node in nodes {
-    if node.index() == index {
-      return Some(node);
+/// Get a node from a slice of already-decoded nodes, e.g. ones held in
+/// memory by a caller that hasn't flushed them to the `tree` store yet.
+fn find_node(nodes: &[Node], index: usize) -> Option<Node> {
+  nodes.iter().find(|node| node.index() == index).cloned()
+}
+
+/// Mask for bit `index % 8` within its byte, most-significant bit first.
+fn bit_mask(index: usize) -> u8 {
+  0x80 >> (index % 8)
+}
+
+/// Pull the `(magic, version, entry_size, algorithm_name)` fields out of a
+/// 32-byte SLEEP header.
+fn parse_header(buf: &[u8]) -> Result<(u32, u8, u16, String), Error> {
+  ensure!(buf.len() >= 8, "SLEEP header is shorter than 8 bytes");
+
+  let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+  let version = buf[4];
+  let entry_size = u16::from_be_bytes([buf[5], buf[6]]);
+  let name_len = buf[7] as usize;
+
+  ensure!(
+    buf.len() >= 8 + name_len,
+    "SLEEP header algorithm name length ({}) overruns the header",
+    name_len
+  );
+
+  let name = String::from_utf8_lossy(&buf[8..8 + name_len]).into_owned();
+  Ok((magic, version, entry_size, name))
+}
+
+/// Compare an on-disk SLEEP header against the one this version of the
+/// crate would have written, erroring out on the first field that differs.
+fn validate_header(name: &str, existing: &[u8], expected: &[u8]) -> Result<(), Error> {
+  let (magic, version, entry_size, algorithm) = parse_header(existing)?;
+  let (exp_magic, exp_version, exp_entry_size, exp_algorithm) = parse_header(expected)?;
+
+  ensure!(
+    magic == exp_magic,
+    "{} store: unexpected magic word 0x{:08x} (expected 0x{:08x})",
+    name,
+    magic,
+    exp_magic
+  );
+  ensure!(
+    version == exp_version,
+    "{} store: unsupported header version {} (expected {})",
+    name,
+    version,
+    exp_version
+  );
+  ensure!(
+    entry_size == exp_entry_size,
+    "{} store: unexpected entry size {} (expected {})",
+    name,
+    entry_size,
+    exp_entry_size
+  );
+  ensure!(
+    algorithm == exp_algorithm,
+    "{} store: unexpected algorithm \"{}\" (expected \"{}\")",
+    name,
+    algorithm,
+    exp_algorithm
+  );
+
+  Ok(())
+}
+
+/// Leaf hash: `BLAKE2b(0x00 || uint64_be(data.len()) || data)`.
+fn hash_leaf(data: &[u8]) -> Vec<u8> {
+  let mut hasher = Blake2b::new(32);
+  hasher.update(&[0x00]);
+  hasher.update(&(data.len() as u64).to_be_bytes());
+  hasher.update(data);
+  hasher.finalize().as_bytes().to_vec()
+}
+
+/// Parent hash: `BLAKE2b(0x01 || uint64_be(size) || left || right)`.
+fn hash_parent(size: usize, left: &[u8], right: &[u8]) -> Vec<u8> {
+  let mut hasher = Blake2b::new(32);
+  hasher.update(&[0x01]);
+  hasher.update(&(size as u64).to_be_bytes());
+  hasher.update(left);
+  hasher.update(right);
+  hasher.finalize().as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{ram, KeyPair, Store, Storage, HEADER_OFFSET};
+
+  #[test]
+  fn open_rejects_a_corrupted_header() {
+    let mut corrupted_tree = ram::Sync::default();
+    let mut header = super::create_tree().to_vec();
+    header[0] = !header[0]; // corrupt the magic word
+    corrupted_tree.write(0, &header).unwrap();
+
+    let corrupted_tree = std::cell::Cell::new(Some(corrupted_tree));
+    let result = Storage::open(KeyPair::default(), move |store: Store| match store {
+      Store::Tree => corrupted_tree.take().unwrap(),
+      _ => ram::Sync::default(),
+    });
+
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn open_does_not_clobber_existing_data() {
+    let dir = std::env::temp_dir().join(format!(
+      "hypercore-red-test-open-no-clobber-{}",
+      std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    {
+      let mut storage = Storage::new(KeyPair::default(), dir.clone()).unwrap();
+      storage.append(b"hello").unwrap();
     }
+
+    let mut reopened = Storage::new(KeyPair::default(), dir.clone()).unwrap();
+    assert_eq!(reopened.get_data(0).unwrap(), b"hello");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn append_then_get_data_round_trips() {
+    let mut storage = Storage::default();
+
+    let index = storage.append(b"hello").unwrap();
+    assert_eq!(index, 0);
+    assert_eq!(storage.get_data(index).unwrap(), b"hello");
+
+    let index = storage.append(b"world").unwrap();
+    assert_eq!(index, 1);
+    assert_eq!(storage.get_data(index).unwrap(), b"world");
+    assert_eq!(storage.get_data(0).unwrap(), b"hello");
+  }
+
+  #[test]
+  fn append_then_verify_succeeds() {
+    let mut storage = Storage::default();
+
+    for block in &[&b"one"[..], &b"two"[..], &b"three"[..]] {
+      storage.append(block).unwrap();
+    }
+
+    storage.verify().unwrap();
+  }
+
+  #[test]
+  fn has_range_straddles_a_byte_boundary() {
+    let mut storage = Storage::default();
+    for i in 0..10 {
+      storage.append(format!("block-{}", i).as_bytes()).unwrap();
+    }
+
+    assert!(storage.has_range(3, 10).unwrap());
+    assert!(storage.has_all().unwrap());
+  }
+
+  #[test]
+  fn has_range_reports_a_block_that_was_never_appended() {
+    let mut storage = Storage::default();
+    for i in 0..5 {
+      storage.append(format!("block-{}", i).as_bytes()).unwrap();
+    }
+
+    assert!(!storage.has(10).unwrap());
+    assert!(!storage.has_range(0, 11).unwrap());
+  }
+
+  #[test]
+  fn with_cache_serves_hits_without_rereading_the_store() {
+    let mut storage =
+      Storage::with_cache(KeyPair::default(), |_store: Store| ram::Sync::default(), 8).unwrap();
+
+    storage.append(b"hello").unwrap();
+    let node = storage.get_node(0).unwrap();
+
+    // Corrupt the node in the store directly: if a later `get_node(0)` actually
+    // re-read the store instead of hitting the cache, this would change
+    // (or fail to decode).
+    let garbage = vec![0xff; 40];
+    storage.tree.write(HEADER_OFFSET, &garbage).unwrap();
+
+    assert_eq!(storage.get_node(0).unwrap().hash(), node.hash());
+  }
+
+  #[test]
+  fn discovery_key_is_deterministic_for_a_fixed_key() {
+    let storage = Storage::default();
+    assert_eq!(storage.discovery_key(), storage.discovery_key());
   }
-  None
 }
\ No newline at end of file